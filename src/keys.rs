@@ -0,0 +1,61 @@
+//! Mapping from browser keyboard events to logical keys.
+
+/// The modifier keys that were held down alongside a key press.
+///
+/// This mirrors the independent `ctrlKey`/`altKey`/`shiftKey`/`metaKey` flags
+/// a browser `KeyboardEvent` exposes, so callers don't need to pre-combine a
+/// key and its modifiers into a single string before we can reason about it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub meta: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers { ctrl: false, alt: false, shift: false, meta: false };
+
+    /// Whether the modifier word-navigation shortcuts (Ctrl on
+    /// Windows/Linux, Alt/Option on Mac) is held.
+    pub fn is_word_modifier(&self) -> bool {
+        self.ctrl || self.alt
+    }
+}
+
+/// A logical key, decoupled from any particular browser event representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Character(char),
+    Backspace,
+    Delete,
+    Enter,
+    ArrowLeft,
+    ArrowRight,
+    ArrowUp,
+    ArrowDown,
+}
+
+impl Key {
+    /// Parse a `KeyboardEvent.key` string into a logical `Key`.
+    ///
+    /// Returns `None` for keys we don't have any handling for (e.g. `Shift`).
+    pub fn from_str(key_val: &str) -> Option<Key> {
+        match key_val {
+            "Backspace" => Some(Key::Backspace),
+            "Delete" => Some(Key::Delete),
+            "Enter" => Some(Key::Enter),
+            "ArrowLeft" => Some(Key::ArrowLeft),
+            "ArrowRight" => Some(Key::ArrowRight),
+            "ArrowUp" => Some(Key::ArrowUp),
+            "ArrowDown" => Some(Key::ArrowDown),
+            _ => {
+                let mut chars = key_val.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(Key::Character(c)),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
@@ -7,15 +7,47 @@ mod keys;
 mod state;
 mod utils;
 
-use std::mem;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 
 use cfg_if::cfg_if;
 use virtual_dom_rs::{VirtualNode, VElement};
 use wasm_bindgen::prelude::*;
 use web_sys::{Element, Node, NodeList, Range};
 
-use crate::keys::Key;
-use crate::state::{State, Direction};
+use crate::keys::{Key, Modifiers};
+use crate::state::{State, RenderGroup, Token, contains_reserved_chars, render_group_to_virtual_node};
+
+/// Handle identifying a bound compose area, returned by `bind_to`.
+pub type ContextHandle = u32;
+
+thread_local! {
+    /// All live compose areas, keyed by the handle `bind_to` returned for
+    /// them. This lets a single page host several independent compose areas
+    /// without any of them touching raw pointers or `unsafe`.
+    static CONTEXTS: RefCell<HashMap<ContextHandle, Context>> = RefCell::new(HashMap::new());
+    static NEXT_HANDLE: Cell<ContextHandle> = Cell::new(0);
+}
+
+/// Register `context` and return the handle it was stored under.
+fn register(context: Context) -> ContextHandle {
+    let handle = NEXT_HANDLE.with(|next| {
+        let handle = next.get();
+        next.set(handle + 1);
+        handle
+    });
+    CONTEXTS.with(|contexts| contexts.borrow_mut().insert(handle, context));
+    handle
+}
+
+/// Run `f` with mutable access to the context registered under `handle`.
+fn with_context<R>(handle: ContextHandle, f: impl FnOnce(&mut Context) -> R) -> R {
+    CONTEXTS.with(|contexts| {
+        let mut contexts = contexts.borrow_mut();
+        let context = contexts.get_mut(&handle).expect("invalid context handle");
+        f(context)
+    })
+}
 
 cfg_if! {
     // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -42,9 +74,89 @@ fn wrap(virtual_nodes: Vec<VirtualNode>, wrapper_id: &str) -> VirtualNode {
     wrapper.into()
 }
 
-/// Initialize a new compose area wrapper with the specified `id`.
+/// Patch `wrapper`'s content children (everything but the trailing `<br>`
+/// anchor `bind_to` set up) from `old_groups` to `new_groups`.
+///
+/// This is our own keyed reconciliation, not `virtual_dom_rs::diff`/`patch`:
+/// that pair only ever compares nodes by position, so a token that moved
+/// (say, because a character was typed before it) looks like "remove one
+/// node, insert a different one" to them, which would recreate its DOM node
+/// — losing image load state, focus, etc. Here, a `RenderGroup::Token`'s DOM
+/// node is looked up and reused by its `key` wherever it ends up; a
+/// `RenderGroup::Text` run has no identity of its own, so consecutive text
+/// runs are matched positionally instead, mutating `textContent` in place.
+///
+/// A key is only ever unique within one `State`'s lifetime — `set_contents`
+/// starts a brand-new `State` whose `TokenId`s restart from zero, so a key
+/// surviving a full content swap is a coincidence, not a guarantee the two
+/// tokens are the same. A looked-up node is only reused once its old token
+/// is confirmed equal to the new one; otherwise it's left for the
+/// stale-node cleanup below and a fresh node is created instead.
+fn patch_contents(wrapper: &Element, old_groups: &[RenderGroup], new_groups: &[RenderGroup]) {
+    let children = wrapper.child_nodes();
+    let child_count = children.length();
+    assert!(child_count >= 1, "wrapper should always have its trailing <br> anchor");
+    let anchor = children.get(child_count - 1).expect("trailing <br> should exist");
+    let old_doms: Vec<Node> = (0..child_count - 1)
+        .map(|i| children.get(i).expect("child should exist"))
+        .collect();
+
+    let mut by_key: HashMap<u64, (Node, Token)> = HashMap::new();
+    let mut unkeyed: VecDeque<Node> = VecDeque::new();
+    for (dom, group) in old_doms.iter().zip(old_groups) {
+        match group {
+            RenderGroup::Token { key, token } => { by_key.insert(*key, (dom.clone(), token.clone())); }
+            RenderGroup::Text(_) => unkeyed.push_back(dom.clone()),
+        }
+    }
+
+    let final_doms: Vec<Node> = new_groups.iter().map(|group| match group {
+        RenderGroup::Token { key, token } => match by_key.remove(key) {
+            Some((dom, old_token)) if old_token == *token => dom,
+            _ => render_group_to_virtual_node(group).create_dom_node().node,
+        },
+        RenderGroup::Text(text) => match unkeyed.pop_front() {
+            Some(reused) => {
+                reused.set_text_content(Some(text));
+                reused
+            }
+            None => render_group_to_virtual_node(group).create_dom_node().node,
+        },
+    }).collect();
+
+    // Drop whichever old nodes weren't reused by the loop above.
+    for old in &old_doms {
+        if !final_doms.iter().any(|dom| dom.is_same_node(Some(old))) {
+            wrapper.remove_child(old).expect("Could not remove stale node");
+        }
+    }
+
+    // Walk the final order backward, anchoring each node just before the one
+    // that's already known to be in place behind it. This puts everything
+    // where it belongs without ever computing a numeric insertion index
+    // (which would keep shifting as nodes above are removed/inserted).
+    let mut anchor = anchor;
+    for dom in final_doms.iter().rev() {
+        let in_place = dom.next_sibling().map_or(false, |sibling| sibling.is_same_node(Some(&anchor)));
+        if !in_place {
+            wrapper.insert_before(dom, Some(&anchor)).expect("Could not position node");
+        }
+        anchor = dom.clone();
+    }
+}
+
+/// Initialize a new compose area wrapper with the specified `id`, returning
+/// the handle subsequent calls use to refer to it.
 #[wasm_bindgen]
-pub fn bind_to(id: &str) -> *mut Context {
+pub fn bind_to(id: &str) -> ContextHandle {
+    bind_to_with_contents(id, "")
+}
+
+/// Like `bind_to`, but hydrates the compose area with `contents` (as
+/// produced by `get_contents`) instead of starting from empty content. Pass
+/// an empty string to get the same behavior as `bind_to`.
+#[wasm_bindgen]
+pub fn bind_to_with_contents(id: &str, contents: &str) -> ContextHandle {
     utils::set_panic_hook();
 
     web_sys::console::log_1(&format!("Bind to #{}", id).into());
@@ -53,10 +165,10 @@ pub fn bind_to(id: &str) -> *mut Context {
     let document = window.document().expect("Should have a document on window");
     let wrapper: Element = document.get_element_by_id(id).expect("Did not find element");
 
-    // Initialize the wrapper element with the initial empty DOM.
+    // Initialize the wrapper element with the initial DOM for `contents`.
     // This prevents the case where the wrapper element is not initialized as
     // it should be, which can lead to funny errors when patching.
-    let state = State::new();
+    let state = if contents.is_empty() { State::new() } else { State::deserialize(contents) };
     let initial_vdom: VirtualNode = wrap(state.to_virtual_nodes(), id);
     let initial_dom: Node = initial_vdom.create_dom_node().node;
     wrapper.replace_with_with_node_1(&initial_dom)
@@ -64,11 +176,37 @@ pub fn bind_to(id: &str) -> *mut Context {
 
     web_sys::console::log_1(&format!("Initialized #{}", id).into());
 
-    let ctx = Box::new(Context {
+    register(Context {
         state,
         wrapper_id: id.to_owned(),
-    });
-    Box::into_raw(ctx)
+    })
+}
+
+/// Get the compose area's current content, serialized in the form
+/// `set_contents` can parse back.
+#[wasm_bindgen]
+pub fn get_contents(ctx: ContextHandle) -> String {
+    with_context(ctx, |context| context.state.serialize())
+}
+
+/// Replace the compose area's content with `serialized` (as produced by
+/// `get_contents`), reconciling the DOM through `patch_contents` so the DOM,
+/// caret and state stay consistent.
+#[wasm_bindgen]
+pub fn set_contents(ctx: ContextHandle, serialized: &str) {
+    with_context(ctx, |context| {
+        let window = web_sys::window().expect("no global `window` exists");
+        let document = window.document().expect("should have a document on window");
+        let wrapper = document.get_element_by_id(&context.wrapper_id).expect("did not find element");
+
+        let old_groups = context.state.render_groups();
+        context.state = State::deserialize(serialized);
+        let new_groups = context.state.render_groups();
+
+        patch_contents(&wrapper, &old_groups, &new_groups);
+
+        browser_set_caret_position(&wrapper, &context.state);
+    })
 }
 
 pub fn set_inner_html(id: &str, html: &str) {
@@ -78,32 +216,32 @@ pub fn set_inner_html(id: &str, html: &str) {
     wrapper.set_inner_html(html);
 }
 
-/// A position relative to a node.
-enum Position<'a> {
-    After(&'a Node),
-    Offset(&'a Node, u32),
+/// One side of a DOM selection/caret range: either right after `node` (used
+/// for a position past the very end of the rendered content, i.e. on the
+/// trailing `<br>` anchor), or at a specific offset within `node`.
+enum Position {
+    After(Node),
+    Offset(Node, u32),
 }
 
-fn add_range_at(pos: Position) {
-    web_sys::console::debug_1(&"add_range_at".into());
+/// Set the browser's selection to span from `start` to `end`. Pass the same
+/// point for both to collapse it to an ordinary caret.
+fn select_range(start: Position, end: Position) {
+    web_sys::console::debug_1(&"select_range".into());
 
     let window = web_sys::window().expect("no global `window` exists");
     let document = window.document().expect("should have a document on window");
 
     let range: Range = document.create_range().expect("Could not create range");
-    match pos {
-        Position::After(node) => {
-            range.set_start_after(node).expect("Could not set range start after");
-            range.set_end_after(node).expect("Could not set range end after");
-        }
-        Position::Offset(node, 0) => {
-            range.set_start_before(node).expect("Could not set range start before");
-            range.set_end_before(node).expect("Could not set range end before");
-        }
-        Position::Offset(node, offset) => {
-            range.set_start(node, offset).expect("Could not set range start");
-            range.set_end(node, offset).expect("Could not set range end");
-        }
+    match start {
+        Position::After(node) => range.set_start_after(&node).expect("Could not set range start after"),
+        Position::Offset(node, 0) => range.set_start_before(&node).expect("Could not set range start before"),
+        Position::Offset(node, offset) => range.set_start(&node, offset).expect("Could not set range start"),
+    }
+    match end {
+        Position::After(node) => range.set_end_after(&node).expect("Could not set range end after"),
+        Position::Offset(node, 0) => range.set_end_before(&node).expect("Could not set range end before"),
+        Position::Offset(node, offset) => range.set_end(&node, offset).expect("Could not set range end"),
     }
 
     if let Some(sel) = window.get_selection().expect("Could not get selection from window") {
@@ -114,6 +252,19 @@ fn add_range_at(pos: Position) {
     }
 }
 
+/// Resolve a logical `state::Position` into a DOM-relative `Position`.
+/// `None` (past the end of the rendered content) resolves to right after
+/// the wrapper's last child, which is always the trailing `<br>` anchor.
+fn resolve_position(nodes: &NodeList, node_count: u32, pos: Option<state::Position>) -> Option<Position> {
+    match pos {
+        Some(pos) => nodes.get(pos.index as u32).map(|node| Position::Offset(node, pos.offset as u32)),
+        None => nodes.get(node_count - 1).map(Position::After),
+    }
+}
+
+/// Set the browser's caret/selection from `state`'s `caret_targets` — a
+/// collapsed caret when `caret_start == caret_end`, or an actual selection
+/// otherwise (e.g. after Ctrl+A).
 fn browser_set_caret_position(wrapper: &Element, state: &State) {
     web_sys::console::debug_1(&"browser_set_caret_position".into());
 
@@ -121,23 +272,34 @@ fn browser_set_caret_position(wrapper: &Element, state: &State) {
     let node_count = nodes.length();
     assert_eq!(node_count, state.node_count() as u32 + 1);
 
-    if let Some(pos) = state.find_start_node(Direction::After) {
-        match nodes.get(pos.index as u32) {
-            Some(ref node) => add_range_at(Position::Offset(&node, pos.offset as u32)),
-            None => { /* TODO */ }
-        }
-    } else {
-        // We're at the end of the node list. Use the latest node.
-        match nodes.get(node_count - 1) {
-            Some(ref node) => add_range_at(Position::After(&node)),
-            None => { /* TODO */ },
-        }
+    let (start, end) = state.caret_targets();
+    match (resolve_position(&nodes, node_count, start), resolve_position(&nodes, node_count, end)) {
+        (Some(start), Some(end)) => select_range(start, end),
+        _ => { /* TODO */ }
     }
 }
 
 /// Return whether the default event handler should be prevented from running.
 #[wasm_bindgen]
-pub fn process_key(ctx: *mut Context, key_val: &str) -> bool {
+pub fn process_key(ctx: ContextHandle, key_val: &str) -> bool {
+    process_key_with_modifiers(ctx, key_val, false, false, false, false)
+}
+
+/// Like `process_key`, but also takes the modifier keys that were held down
+/// alongside `key_val` (mirroring a browser `KeyboardEvent`'s `ctrlKey`,
+/// `altKey`, `shiftKey` and `metaKey` flags), so shortcuts like Ctrl+Backspace
+/// can be distinguished from a bare Backspace.
+///
+/// Return whether the default event handler should be prevented from running.
+#[wasm_bindgen]
+pub fn process_key_with_modifiers(
+    ctx: ContextHandle,
+    key_val: &str,
+    ctrl_key: bool,
+    alt_key: bool,
+    shift_key: bool,
+    meta_key: bool,
+) -> bool {
     // Validate and parse key value
     if key_val.len() == 0 {
         web_sys::console::warn_1(&"process_key: No key value provided".into());
@@ -147,65 +309,91 @@ pub fn process_key(ctx: *mut Context, key_val: &str) -> bool {
         Some(key) => key,
         None => return false,
     };
+    let modifiers = Modifiers { ctrl: ctrl_key, alt: alt_key, shift: shift_key, meta: meta_key };
 
-    // Dereference context
-    let mut context = unsafe { Box::from_raw(ctx) };
+    with_context(ctx, |context| {
+        // Get the old render groups, to reconcile against below.
+        let old_groups = context.state.render_groups();
 
-    // Get access to wrapper element
-    let window = web_sys::window().expect("no global `window` exists");
-    let document = window.document().expect("should have a document on window");
-    let wrapper = document.get_element_by_id(&context.wrapper_id).expect("did not find element");
+        // Handle input. If this key isn't one we have a shortcut for (e.g. a
+        // bare Ctrl/Cmd+letter we don't recognize), leave the state and DOM
+        // untouched and let the browser run its native behavior instead.
+        if !context.state.handle_key(key, modifiers) {
+            return false;
+        }
 
-    // Get old virtual DOM
-    let old_vdom = wrap(context.state.to_virtual_nodes(), &context.wrapper_id);
+        // Get access to wrapper element
+        let window = web_sys::window().expect("no global `window` exists");
+        let document = window.document().expect("should have a document on window");
+        let wrapper = document.get_element_by_id(&context.wrapper_id).expect("did not find element");
 
-    // Handle input
-    context.state.handle_key(key);
+        // Reconcile the DOM against the new render groups.
+        let new_groups = context.state.render_groups();
+        patch_contents(&wrapper, &old_groups, &new_groups);
 
-    // Get new virtual DOM
-    let new_vdom = wrap(context.state.to_virtual_nodes(), &context.wrapper_id);
+        // Update the caret position in the browser
+        browser_set_caret_position(&wrapper, &context.state);
 
-    // Do the DOM diffing
-    let patches = virtual_dom_rs::diff(&old_vdom, &new_vdom);
+        // We handled the event, so prevent the default event from being handled.
+        true
+    })
+}
 
-    web_sys::console::log_1(&format!("RS: Old vdom: {:?}", &old_vdom).into());
-    web_sys::console::log_1(&format!("RS: New vdom: {:?}", &new_vdom).into());
-    web_sys::console::log_1(&format!("RS: Patches {:?}", &patches).into());
+/// Insert an inline token (an emoji or a mention) at the current caret
+/// position.
+///
+/// `kind` is `"emoji"` with a `"<shortcode>|<url>"` payload, or `"mention"`
+/// with an `"<id>|<label>"` payload. Returns whether the token was
+/// recognized and inserted.
+///
+/// A field containing one of `serialize`'s reserved control characters is
+/// rejected rather than inserted, since it isn't necessarily user-typed (it
+/// may come from elsewhere, e.g. a federated mention label) and would
+/// otherwise silently corrupt a later `get_contents`/`set_contents` round
+/// trip.
+#[wasm_bindgen]
+pub fn insert_token(ctx: ContextHandle, kind: &str, payload: &str) -> bool {
+    let token = match (kind, payload.split_once('|')) {
+        ("emoji", Some((shortcode, url))) if !contains_reserved_chars(shortcode) && !contains_reserved_chars(url) =>
+            Token::Emoji { shortcode: shortcode.to_owned(), url: url.to_owned() },
+        ("mention", Some((id, label))) if !contains_reserved_chars(id) && !contains_reserved_chars(label) =>
+            Token::Mention { id: id.to_owned(), label: label.to_owned() },
+        _ => {
+            web_sys::console::warn_1(&format!("insert_token: Unrecognized kind/payload, or payload contains a reserved character: {:?}/{:?}", kind, payload).into());
+            return false;
+        }
+    };
 
-    // Patch the current DOM
-    virtual_dom_rs::patch(wrapper.clone(), &patches);
+    with_context(ctx, |context| {
+        let window = web_sys::window().expect("no global `window` exists");
+        let document = window.document().expect("should have a document on window");
+        let wrapper = document.get_element_by_id(&context.wrapper_id).expect("did not find element");
 
-    // Update the caret position in the browser
-    browser_set_caret_position(&wrapper, &context.state);
+        let old_groups = context.state.render_groups();
+        context.state.insert_token(token);
+        let new_groups = context.state.render_groups();
 
-    // Forget about the context box to prevent it from being freed
-    mem::forget(context);
+        patch_contents(&wrapper, &old_groups, &new_groups);
 
-    // We handled the event, so prevent the default event from being handled.
-    true
+        browser_set_caret_position(&wrapper, &context.state);
+
+        true
+    })
 }
 
 /// Set the start and end of the caret position (relative to the HTML).
 #[wasm_bindgen]
-pub fn update_caret_position(ctx: *mut Context, start: usize, end: usize) {
-    // Dereference context
-    let mut context = unsafe { Box::from_raw(ctx) };
-
-    // Update state
+pub fn update_caret_position(ctx: ContextHandle, start: usize, end: usize) {
     if end < start {
         return;
     }
-    context.state.set_caret_position(start, end);
-
-    // Forget about the context box to prevent it from being freed
-    mem::forget(context);
+    with_context(ctx, |context| context.state.set_caret_position(start, end));
 }
 
-/// Dipose all state related to the specified context.
+/// Dispose all state related to the specified context.
 ///
-/// After calling this function, the context may not be used anymore.
+/// After calling this function, the handle may not be used anymore.
 #[wasm_bindgen]
-pub fn dispose(ctx: *mut Context) {
-    // Dereference context and drop
-    unsafe { Box::from_raw(ctx); }
+pub fn dispose(ctx: ContextHandle) {
+    CONTEXTS.with(|contexts| contexts.borrow_mut().remove(&ctx));
 }
@@ -0,0 +1,570 @@
+//! The compose area's content model: the text and inline tokens it holds,
+//! the caret, and the logic for turning key presses into edits.
+
+use virtual_dom_rs::{VElement, VirtualNode};
+
+use crate::keys::{Key, Modifiers};
+
+/// A DOM child index plus an offset into that child, used to place the
+/// browser's native caret.
+pub struct Position {
+    pub index: usize,
+    pub offset: usize,
+}
+
+/// Field/segment separators used by `State::serialize`; both are ASCII
+/// control characters that can't come from a keyboard, so plain text never
+/// needs escaping.
+const SEGMENT_SEP: char = '\u{1e}';
+const FIELD_SEP: char = '\u{1f}';
+
+/// Whether `s` contains either of `serialize`'s delimiter characters.
+///
+/// A user can't type these from a keyboard, but a `Token`'s fields don't
+/// necessarily come from the keyboard — they can be passed in by a caller
+/// sourcing a mention label or emoji URL from elsewhere (another federated
+/// service, pasted rich content, ...). Callers that build a `Token` from
+/// untrusted input should reject fields this returns `true` for, rather than
+/// let them silently corrupt the `serialize`/`deserialize` round trip.
+pub(crate) fn contains_reserved_chars(s: &str) -> bool {
+    s.contains(SEGMENT_SEP) || s.contains(FIELD_SEP)
+}
+
+/// An atomic, non-editable inline token (as opposed to plain text).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Emoji { shortcode: String, url: String },
+    Mention { id: String, label: String },
+}
+
+/// One logical position of content: either a single character, or a whole
+/// inline token. Tokens always occupy exactly one logical position, no
+/// matter how they end up rendered, so the caret can only ever sit before or
+/// after one, never inside it.
+///
+/// Each unit also carries a `TokenId` assigned once, when it's inserted, and
+/// never reassigned afterwards. `render_groups` surfaces a token's id as a
+/// stable key, which `lib.rs` uses to reconcile the real DOM against edits
+/// made elsewhere in the content (see `RenderGroup`).
+#[derive(Debug, Clone, PartialEq)]
+enum Unit {
+    Char(char, TokenId),
+    Token(Token, TokenId),
+}
+
+/// A stable identifier for a `Unit`, used as a reconciliation key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TokenId(u64);
+
+/// One rendered unit of content, as `render_groups` groups it: a run of
+/// consecutive characters, or a single token. Lines up 1:1 with the
+/// non-`<br>` nodes `to_virtual_nodes` produces.
+///
+/// A `Token` group's `key` is stable across edits to the rest of the
+/// content (insertions/deletions elsewhere never change it), so callers
+/// that keep their own record of which DOM node a key maps to can reuse
+/// that node — e.g. to avoid recreating an `<img>` every time a keystroke
+/// elsewhere in the message changes. A `Text` group has no such identity:
+/// which characters end up merged into one run depends on what's adjacent
+/// to it, so it's addressed positionally instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderGroup {
+    Text(String),
+    Token { key: u64, token: Token },
+}
+
+/// The compose area's content and caret.
+pub struct State {
+    content: Vec<Unit>,
+    caret_start: usize,
+    caret_end: usize,
+    next_id: u64,
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            content: Vec::new(),
+            caret_start: 0,
+            caret_end: 0,
+            next_id: 0,
+        }
+    }
+
+    fn alloc_id(&mut self) -> TokenId {
+        let id = TokenId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// The number of DOM nodes `to_virtual_nodes` renders for the content
+    /// itself, not counting the trailing caret anchor.
+    pub fn node_count(&self) -> usize {
+        render_group_lengths(&self.content).len()
+    }
+
+    /// Group the current content the way it renders: a run of consecutive
+    /// characters becomes one `RenderGroup::Text`, and each token becomes
+    /// its own `RenderGroup::Token`. Lines up 1:1 with the non-`<br>` nodes
+    /// `to_virtual_nodes` produces.
+    pub fn render_groups(&self) -> Vec<RenderGroup> {
+        let mut groups = Vec::new();
+        let mut i = 0;
+        while i < self.content.len() {
+            match &self.content[i] {
+                Unit::Char(..) => {
+                    let mut text = String::new();
+                    while let Some(Unit::Char(c, _)) = self.content.get(i) {
+                        text.push(*c);
+                        i += 1;
+                    }
+                    groups.push(RenderGroup::Text(text));
+                }
+                Unit::Token(token, id) => {
+                    groups.push(RenderGroup::Token { key: id.0, token: token.clone() });
+                    i += 1;
+                }
+            }
+        }
+        groups
+    }
+
+    /// Render the current content as virtual nodes. A trailing `<br>` is
+    /// always appended so the browser has somewhere to place the caret
+    /// after the last unit (or in otherwise-empty content).
+    pub fn to_virtual_nodes(&self) -> Vec<VirtualNode> {
+        let mut nodes: Vec<VirtualNode> = self.render_groups().iter().map(render_group_to_virtual_node).collect();
+        nodes.push(VElement::new("br").into());
+        nodes
+    }
+
+    /// Find the DOM node/offset a logical position `pos` corresponds to.
+    /// `None` means `pos` is at or past the end of the rendered content, so
+    /// there's no content node to anchor it to — the caller should fall back
+    /// to the trailing `<br>` anchor instead.
+    fn node_position_at(&self, pos: usize) -> Option<Position> {
+        let groups = render_group_lengths(&self.content);
+        let last = groups.len().saturating_sub(1);
+        let mut remaining = pos;
+        for (index, &units) in groups.iter().enumerate() {
+            // Right after a token, anchor to the start of the next node rather
+            // than to an offset inside the token's own node.
+            let is_token = matches!(self.content[pos - remaining], Unit::Token(..));
+            if remaining < units || (remaining == units && index != last && !is_token) {
+                return Some(Position { index, offset: remaining });
+            }
+            remaining -= units;
+        }
+        None
+    }
+
+    /// The DOM node/offset the caret's start and end should be placed at, so
+    /// the browser can render a collapsed caret (start == end) or an actual
+    /// selection (start != end, e.g. after `select_all`).
+    pub fn caret_targets(&self) -> (Option<Position>, Option<Position>) {
+        (self.node_position_at(self.caret_start), self.node_position_at(self.caret_end))
+    }
+
+    pub fn set_caret_position(&mut self, start: usize, end: usize) {
+        self.caret_start = start;
+        self.caret_end = end;
+    }
+
+    /// Handle a key press, applying `modifiers` to decide which variant of
+    /// the key's behavior to use.
+    ///
+    /// Returns whether the key was handled. `false` means the caller should
+    /// let the browser's native behavior run instead (e.g. Ctrl+C/V/X/Z, or
+    /// any other Ctrl/Cmd+letter combination we don't have a shortcut for) —
+    /// none of those should fall through to inserting the letter itself.
+    pub fn handle_key(&mut self, key: Key, modifiers: Modifiers) -> bool {
+        match key {
+            Key::Character(c) if modifiers.ctrl && (c == 'a' || c == 'A') => self.select_all(),
+            Key::Character(_) if modifiers.ctrl || modifiers.meta => return false,
+            Key::Character(c) => self.insert_char(c),
+            Key::Enter => self.insert_char('\n'),
+            Key::Backspace if modifiers.is_word_modifier() => self.delete_word_backward(),
+            Key::Backspace => self.delete_backward(),
+            Key::Delete if modifiers.is_word_modifier() => self.delete_word_forward(),
+            Key::Delete => self.delete_forward(),
+            // With an active selection, the arrow keys collapse to its near
+            // edge first, the same as a collapsed caret moving by one would
+            // land on; they don't also step one further past it.
+            Key::ArrowLeft if self.caret_start != self.caret_end => self.move_caret_to(self.caret_start),
+            Key::ArrowLeft => self.move_caret_to(self.caret_start.saturating_sub(1)),
+            Key::ArrowRight if self.caret_start != self.caret_end => self.move_caret_to(self.caret_end),
+            Key::ArrowRight => self.move_caret_to(self.caret_end + 1),
+            Key::ArrowUp | Key::ArrowDown => {}
+        }
+        true
+    }
+
+    /// Insert `token` at the current caret position, replacing any
+    /// selection, the same way a typed character would be.
+    pub fn insert_token(&mut self, token: Token) {
+        let id = self.alloc_id();
+        self.replace_range(self.caret_start, self.caret_end, vec![Unit::Token(token, id)]);
+    }
+
+    /// Serialize the content to a stable plain-text form that `deserialize`
+    /// can parse back into an equivalent `State`.
+    ///
+    /// Each run of characters and each token becomes its own segment,
+    /// separated by `SEGMENT_SEP`; a token segment's fields are separated by
+    /// `FIELD_SEP`. Both are ASCII control characters a user can't type, so
+    /// plain text never needs escaping.
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        let mut i = 0;
+        let mut first = true;
+        while i < self.content.len() {
+            if !first {
+                out.push(SEGMENT_SEP);
+            }
+            first = false;
+            match &self.content[i] {
+                Unit::Char(..) => {
+                    out.push_str("text:");
+                    while let Some(Unit::Char(c, _)) = self.content.get(i) {
+                        out.push(*c);
+                        i += 1;
+                    }
+                }
+                Unit::Token(Token::Emoji { shortcode, url }, _) => {
+                    out.push_str("emoji:");
+                    out.push_str(shortcode);
+                    out.push(FIELD_SEP);
+                    out.push_str(url);
+                    i += 1;
+                }
+                Unit::Token(Token::Mention { id, label }, _) => {
+                    out.push_str("mention:");
+                    out.push_str(id);
+                    out.push(FIELD_SEP);
+                    out.push_str(label);
+                    i += 1;
+                }
+            }
+        }
+        out
+    }
+
+    /// Parse content previously produced by `serialize` back into a `State`,
+    /// with the caret placed at the end.
+    pub fn deserialize(serialized: &str) -> State {
+        let mut state = State::new();
+        if !serialized.is_empty() {
+            for segment in serialized.split(SEGMENT_SEP) {
+                if let Some(text) = segment.strip_prefix("text:") {
+                    for c in text.chars() {
+                        let id = state.alloc_id();
+                        state.content.push(Unit::Char(c, id));
+                    }
+                } else if let Some(rest) = segment.strip_prefix("emoji:") {
+                    if let Some((shortcode, url)) = rest.split_once(FIELD_SEP) {
+                        let id = state.alloc_id();
+                        state.content.push(Unit::Token(Token::Emoji { shortcode: shortcode.to_owned(), url: url.to_owned() }, id));
+                    }
+                } else if let Some(rest) = segment.strip_prefix("mention:") {
+                    if let Some((id, label)) = rest.split_once(FIELD_SEP) {
+                        let unit_id = state.alloc_id();
+                        state.content.push(Unit::Token(Token::Mention { id: id.to_owned(), label: label.to_owned() }, unit_id));
+                    }
+                }
+            }
+        }
+        state.caret_start = state.content.len();
+        state.caret_end = state.content.len();
+        state
+    }
+
+    fn move_caret_to(&mut self, pos: usize) {
+        let pos = pos.min(self.content.len());
+        self.caret_start = pos;
+        self.caret_end = pos;
+    }
+
+    fn insert_char(&mut self, c: char) {
+        let id = self.alloc_id();
+        self.replace_range(self.caret_start, self.caret_end, vec![Unit::Char(c, id)]);
+    }
+
+    fn delete_backward(&mut self) {
+        if self.caret_start == self.caret_end {
+            if self.caret_start == 0 {
+                return;
+            }
+            self.replace_range(self.caret_start - 1, self.caret_end, vec![]);
+        } else {
+            self.replace_range(self.caret_start, self.caret_end, vec![]);
+        }
+    }
+
+    fn delete_forward(&mut self) {
+        if self.caret_start == self.caret_end {
+            if self.caret_end == self.content.len() {
+                return;
+            }
+            self.replace_range(self.caret_start, self.caret_end + 1, vec![]);
+        } else {
+            self.replace_range(self.caret_start, self.caret_end, vec![]);
+        }
+    }
+
+    /// Delete backward from the caret to the previous word boundary.
+    fn delete_word_backward(&mut self) {
+        let start = prev_word_boundary(&self.content, self.caret_start);
+        self.replace_range(start, self.caret_end, vec![]);
+    }
+
+    /// Delete forward from the caret to the next word boundary.
+    fn delete_word_forward(&mut self) {
+        let end = next_word_boundary(&self.content, self.caret_end);
+        self.replace_range(self.caret_start, end, vec![]);
+    }
+
+    fn select_all(&mut self) {
+        self.caret_start = 0;
+        self.caret_end = self.content.len();
+    }
+
+    /// Replace the units in `[start, end)` with `replacement`, then collapse
+    /// the caret to just after the inserted units.
+    fn replace_range(&mut self, start: usize, end: usize, replacement: Vec<Unit>) {
+        let len = self.content.len();
+        let start = start.min(len);
+        let end = end.min(len).max(start);
+
+        let mut new_content = self.content[..start].to_vec();
+        let inserted = replacement.len();
+        new_content.extend(replacement);
+        new_content.extend_from_slice(&self.content[end..]);
+        self.content = new_content;
+
+        let new_pos = start + inserted;
+        self.caret_start = new_pos;
+        self.caret_end = new_pos;
+    }
+}
+
+/// Render a single `RenderGroup` as a `VirtualNode`. The `data-key`
+/// attribute on a token node is for debugging/inspection only — it is
+/// `lib.rs`'s DOM reconciliation (keyed off `RenderGroup::Token`'s `key`
+/// field, not this attribute) that actually reuses the node across edits.
+pub fn render_group_to_virtual_node(group: &RenderGroup) -> VirtualNode {
+    match group {
+        RenderGroup::Text(text) => VirtualNode::text(text.as_str()),
+        RenderGroup::Token { key, token } => token_to_virtual_node(token, *key),
+    }
+}
+
+fn token_to_virtual_node(token: &Token, key: u64) -> VirtualNode {
+    match token {
+        Token::Emoji { shortcode, url } => {
+            let mut img = VElement::new("img");
+            img.props.insert("data-key".into(), key.to_string());
+            img.props.insert("src".into(), url.clone());
+            img.props.insert("alt".into(), shortcode.clone());
+            img.props.insert("contenteditable".into(), "false".into());
+            img.props.insert("data-emoji-shortcode".into(), shortcode.clone());
+            img.into()
+        }
+        Token::Mention { id, label } => {
+            let mut span = VElement::new("span");
+            span.props.insert("data-key".into(), key.to_string());
+            span.props.insert("contenteditable".into(), "false".into());
+            span.props.insert("data-mention-id".into(), id.clone());
+            span.children = vec![VirtualNode::text(label.as_str())];
+            span.into()
+        }
+    }
+}
+
+/// The logical-position length of each DOM node `to_virtual_nodes` would
+/// render for `content` (not counting the trailing `<br>`). A run of
+/// consecutive characters renders as one text node spanning that many
+/// positions; a token renders as one node spanning a single position.
+fn render_group_lengths(content: &[Unit]) -> Vec<usize> {
+    let mut groups = Vec::new();
+    let mut i = 0;
+    while i < content.len() {
+        match content[i] {
+            Unit::Char(..) => {
+                let start = i;
+                while matches!(content.get(i), Some(Unit::Char(..))) {
+                    i += 1;
+                }
+                groups.push(i - start);
+            }
+            Unit::Token(..) => {
+                groups.push(1);
+                i += 1;
+            }
+        }
+    }
+    groups
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Scan `content` backward from `pos`, skipping trailing whitespace, then
+/// stopping at the first whitespace/punctuation transition. A token is
+/// atomic and always stops the scan rather than being skipped over.
+fn prev_word_boundary(content: &[Unit], pos: usize) -> usize {
+    let mut idx = pos.min(content.len());
+    while idx > 0 && matches!(content[idx - 1], Unit::Char(c, _) if c.is_whitespace()) {
+        idx -= 1;
+    }
+    if idx > 0 {
+        match content[idx - 1] {
+            Unit::Token(..) => return idx - 1,
+            Unit::Char(c, _) => {
+                let word_like = is_word_char(c);
+                while idx > 0 {
+                    match content[idx - 1] {
+                        Unit::Char(c, _) if !c.is_whitespace() && is_word_char(c) == word_like => idx -= 1,
+                        _ => break,
+                    }
+                }
+            }
+        }
+    }
+    idx
+}
+
+/// The forward counterpart of `prev_word_boundary`.
+fn next_word_boundary(content: &[Unit], pos: usize) -> usize {
+    let len = content.len();
+    let mut idx = pos.min(len);
+    while idx < len && matches!(content[idx], Unit::Char(c, _) if c.is_whitespace()) {
+        idx += 1;
+    }
+    if idx < len {
+        match content[idx] {
+            Unit::Token(..) => return idx + 1,
+            Unit::Char(c, _) => {
+                let word_like = is_word_char(c);
+                while idx < len {
+                    match content[idx] {
+                        Unit::Char(c, _) if !c.is_whitespace() && is_word_char(c) == word_like => idx += 1,
+                        _ => break,
+                    }
+                }
+            }
+        }
+    }
+    idx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_chars(s: &str) -> Vec<Unit> {
+        s.chars().enumerate().map(|(i, c)| Unit::Char(c, TokenId(i as u64))).collect()
+    }
+
+    #[test]
+    fn prev_word_boundary_stops_at_whitespace_before_the_last_word() {
+        let content = make_chars("foo, bar");
+        assert_eq!(prev_word_boundary(&content, content.len()), 5);
+    }
+
+    #[test]
+    fn prev_word_boundary_stops_right_after_a_token() {
+        let content = vec![
+            Unit::Token(Token::Emoji { shortcode: "x".into(), url: "y".into() }, TokenId(0)),
+            Unit::Char('h', TokenId(1)),
+            Unit::Char('i', TokenId(2)),
+        ];
+        assert_eq!(prev_word_boundary(&content, content.len()), 1);
+    }
+
+    #[test]
+    fn next_word_boundary_stops_at_punctuation() {
+        let content = make_chars("foo, bar");
+        assert_eq!(next_word_boundary(&content, 0), 3);
+    }
+
+    #[test]
+    fn next_word_boundary_stops_right_before_a_token() {
+        let content = vec![
+            Unit::Char('h', TokenId(0)),
+            Unit::Char('i', TokenId(1)),
+            Unit::Token(Token::Emoji { shortcode: "x".into(), url: "y".into() }, TokenId(2)),
+        ];
+        assert_eq!(next_word_boundary(&content, 0), 2);
+    }
+
+    #[test]
+    fn select_all_spans_the_whole_content() {
+        let mut state = State::new();
+        state.insert_char('h');
+        state.insert_char('i');
+
+        state.handle_key(Key::Character('a'), Modifiers { ctrl: true, ..Modifiers::NONE });
+
+        assert_eq!((state.caret_start, state.caret_end), (0, 2));
+    }
+
+    #[test]
+    fn arrow_keys_collapse_a_selection_to_its_near_edge_instead_of_overshooting() {
+        let mut state = State::new();
+        state.insert_char('h');
+        state.insert_char('i');
+        state.select_all();
+
+        let mut after_left = state_copy(&state);
+        after_left.handle_key(Key::ArrowLeft, Modifiers::NONE);
+        assert_eq!((after_left.caret_start, after_left.caret_end), (0, 0));
+
+        let mut after_right = state_copy(&state);
+        after_right.handle_key(Key::ArrowRight, Modifiers::NONE);
+        assert_eq!((after_right.caret_start, after_right.caret_end), (2, 2));
+    }
+
+    fn state_copy(state: &State) -> State {
+        State {
+            content: state.content.clone(),
+            caret_start: state.caret_start,
+            caret_end: state.caret_end,
+            next_id: state.next_id,
+        }
+    }
+
+    #[test]
+    fn delete_word_backward_treats_token_as_atomic() {
+        let mut state = State::new();
+        state.insert_token(Token::Emoji { shortcode: "wave".into(), url: "wave.png".into() });
+        state.insert_char('h');
+        state.insert_char('i');
+
+        state.delete_word_backward();
+
+        assert_eq!(state.serialize(), "emoji:wave\u{1f}wave.png");
+    }
+
+    #[test]
+    fn serialize_deserialize_round_trip() {
+        let mut state = State::new();
+        state.insert_char('h');
+        state.insert_char('i');
+        state.insert_token(Token::Mention { id: "42".into(), label: "alice".into() });
+        state.insert_char('!');
+
+        let serialized = state.serialize();
+        let restored = State::deserialize(&serialized);
+
+        assert_eq!(restored.serialize(), serialized);
+        assert_eq!(restored.caret_start, restored.content.len());
+        assert_eq!(restored.caret_end, restored.content.len());
+    }
+
+    #[test]
+    fn serialize_deserialize_round_trip_empty() {
+        let state = State::deserialize("");
+        assert_eq!(state.serialize(), "");
+    }
+}